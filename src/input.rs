@@ -0,0 +1,99 @@
+//! The decoded form of `struct input_event` records read from
+//! `/dev/input/eventN` (the byte-level decoding is `main`'s
+//! `decode_input_event()`), plus helpers `main` uses to turn them into log text.
+
+pub const EV_KEY: u16 = 0x01;
+
+const KEY_PRESS: i32 = 1;
+const KEY_RELEASE: i32 = 0;
+
+const KEY_LEFTSHIFT: u16 = 42;
+const KEY_RIGHTSHIFT: u16 = 54;
+
+#[derive(Debug, Clone, Copy)]
+pub struct InputEvent {
+    pub tv_sec: isize,
+    pub tv_usec: isize,
+    pub type_: u16,
+    pub code: u16,
+    pub value: i32,
+}
+
+pub fn is_key_event(type_: u16) -> bool {
+    type_ == EV_KEY
+}
+
+pub fn is_key_press(value: i32) -> bool {
+    value == KEY_PRESS
+}
+
+pub fn is_key_release(value: i32) -> bool {
+    value == KEY_RELEASE
+}
+
+pub fn is_shift(code: u16) -> bool {
+    code == KEY_LEFTSHIFT || code == KEY_RIGHTSHIFT
+}
+
+/// Translate a `KEY_*` code into the text it should produce, given whether
+/// shift is currently held. Unknown/unprintable codes produce an empty
+/// string so callers can simply append the result to the log.
+pub fn get_key_text(code: u16, shift_pressed: u8) -> String {
+    let shifted = shift_pressed > 0;
+    let text = match code {
+        1 => "[ESC]",
+        2 => if shifted { "!" } else { "1" },
+        3 => if shifted { "@" } else { "2" },
+        4 => if shifted { "#" } else { "3" },
+        5 => if shifted { "$" } else { "4" },
+        6 => if shifted { "%" } else { "5" },
+        7 => if shifted { "^" } else { "6" },
+        8 => if shifted { "&" } else { "7" },
+        9 => if shifted { "*" } else { "8" },
+        10 => if shifted { "(" } else { "9" },
+        11 => if shifted { ")" } else { "0" },
+        12 => if shifted { "_" } else { "-" },
+        13 => if shifted { "+" } else { "=" },
+        14 => "[BACKSPACE]",
+        15 => "\t",
+        16 => if shifted { "Q" } else { "q" },
+        17 => if shifted { "W" } else { "w" },
+        18 => if shifted { "E" } else { "e" },
+        19 => if shifted { "R" } else { "r" },
+        20 => if shifted { "T" } else { "t" },
+        21 => if shifted { "Y" } else { "y" },
+        22 => if shifted { "U" } else { "u" },
+        23 => if shifted { "I" } else { "i" },
+        24 => if shifted { "O" } else { "o" },
+        25 => if shifted { "P" } else { "p" },
+        26 => if shifted { "{" } else { "[" },
+        27 => if shifted { "}" } else { "]" },
+        28 => "\n",
+        30 => if shifted { "A" } else { "a" },
+        31 => if shifted { "S" } else { "s" },
+        32 => if shifted { "D" } else { "d" },
+        33 => if shifted { "F" } else { "f" },
+        34 => if shifted { "G" } else { "g" },
+        35 => if shifted { "H" } else { "h" },
+        36 => if shifted { "J" } else { "j" },
+        37 => if shifted { "K" } else { "k" },
+        38 => if shifted { "L" } else { "l" },
+        39 => if shifted { ":" } else { ";" },
+        40 => if shifted { "\"" } else { "'" },
+        41 => if shifted { "~" } else { "`" },
+        43 => if shifted { "|" } else { "\\" },
+        44 => if shifted { "Z" } else { "z" },
+        45 => if shifted { "X" } else { "x" },
+        46 => if shifted { "C" } else { "c" },
+        47 => if shifted { "V" } else { "v" },
+        48 => if shifted { "B" } else { "b" },
+        49 => if shifted { "N" } else { "n" },
+        50 => if shifted { "M" } else { "m" },
+        51 => if shifted { "<" } else { "," },
+        52 => if shifted { ">" } else { "." },
+        53 => if shifted { "?" } else { "/" },
+        57 => " ",
+        _ => "",
+    };
+    text.to_string()
+}
@@ -11,46 +11,244 @@ use crate::input::{
     get_key_text, is_key_event, is_key_press, is_key_release, is_shift, InputEvent,
 };
 
+use std::collections::HashMap;
 use std::error::Error;
+use std::ffi::CStr;
 use std::fs::{File, OpenOptions};
 use std::io::{BufRead, Read, Write};
-use std::process::{exit, Command};
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::path::Path;
+use std::process::exit;
 use std::{env, mem};
 
 use getopts::Options;
 
 const VERSION: &'static str = env!("CARGO_PKG_VERSION");
 
+const DEV_INPUT_DIR: &str = "/dev/input/";
+
+/// Output format for the key log, selected with `--format`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Format {
+    /// A flattened transcript of decoded key text, each key prefixed with
+    /// the `[path]` of the device that produced it.
+    Text,
+    /// One JSON object per key event, preserving timing, raw codes and
+    /// shift state for downstream tooling (keystroke-dynamics analysis,
+    /// replay).
+    Jsonl,
+    /// Unmodified `input_event` records, re-feedable through
+    /// `decode_input_event()`.
+    Raw,
+}
+
+impl std::str::FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(Format::Text),
+            "jsonl" => Ok(Format::Jsonl),
+            "raw" => Ok(Format::Raw),
+            other => Err(format!(
+                "unknown format '{}': expected text, jsonl or raw",
+                other
+            )),
+        }
+    }
+}
+
+fn json_escape(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\t' => escaped.push_str("\\t"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Build one `jsonl`-format line for `event`, covering both presses and
+/// releases so downstream tooling can reconstruct dwell/flight times
+/// instead of only ever seeing `value: 1`.
+fn jsonl_line(device: &Device, event: &InputEvent) -> String {
+    let key = get_key_text(event.code, device.shift_pressed);
+    format!(
+        "{{\"device\":\"{}\",\"tv_sec\":{},\"tv_usec\":{},\
+        \"code\":{},\"value\":{},\"key\":\"{}\",\"shift\":{}}}\n",
+        json_escape(&device.path),
+        event.tv_sec,
+        event.tv_usec,
+        event.code,
+        event.value,
+        json_escape(&key),
+        device.shift_pressed > 0
+    )
+}
+
+fn write_log_line(log_file: &mut File, line: &str) {
+    let text = line.as_bytes();
+    let num_bytes = log_file.write(text).unwrap_or_else(|e| panic!("{}", e));
+    if num_bytes != text.len() {
+        panic!("Error while writing to log file");
+    }
+}
+
 #[derive(Debug)]
 struct Config {
-    device_file: String,
+    device_files: Vec<String>,
     log_file: String,
+    grab: bool,
+    format: Format,
 }
 
 impl Config {
-    fn new(device_file: String, log_file: String) -> Self {
+    fn new(device_files: Vec<String>, log_file: String, grab: bool, format: Format) -> Self {
         Self {
-            device_file,
+            device_files,
             log_file,
+            grab,
+            format,
+        }
+    }
+}
+
+/// One opened, non-blocking input device that has been registered with
+/// epoll, plus the per-device state that `main`'s event loop needs.
+struct Device {
+    path: String,
+    file: File,
+    shift_pressed: u8,
+}
+
+/// Put `fd` in non-blocking mode so repeated reads in the epoll drain loop
+/// can be stopped on `EAGAIN` instead of blocking the whole engine.
+fn set_nonblocking(fd: RawFd) -> std::io::Result<()> {
+    let flags = unsafe { libc::fcntl(fd, libc::F_GETFL, 0) };
+    if flags < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    let res = unsafe { libc::fcntl(fd, libc::F_SETFL, flags | libc::O_NONBLOCK) };
+    if res < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+fn epoll_add(epoll_fd: RawFd, fd: RawFd) -> std::io::Result<()> {
+    let mut event = libc::epoll_event {
+        events: libc::EPOLLIN as u32,
+        u64: fd as u64,
+    };
+    let res = unsafe { libc::epoll_ctl(epoll_fd, libc::EPOLL_CTL_ADD, fd, &mut event) };
+    if res < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+fn epoll_remove(epoll_fd: RawFd, fd: RawFd) {
+    unsafe {
+        libc::epoll_ctl(epoll_fd, libc::EPOLL_CTL_DEL, fd, std::ptr::null_mut());
+    }
+}
+
+/// Watch `/dev/input/` for newly-created device nodes, so keyboards plugged
+/// in after startup are picked up without restarting `kl`.
+fn watch_dev_input() -> std::io::Result<File> {
+    let inotify_fd = unsafe { libc::inotify_init1(libc::IN_NONBLOCK) };
+    if inotify_fd < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    let inotify_file = unsafe { File::from_raw_fd(inotify_fd) };
+
+    let path = std::ffi::CString::new(DEV_INPUT_DIR).unwrap();
+    let watch = unsafe { libc::inotify_add_watch(inotify_fd, path.as_ptr(), libc::IN_CREATE) };
+    if watch < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(inotify_file)
+}
+
+/// Drain pending inotify events, returning the full paths of any newly
+/// created nodes under `/dev/input/`.
+fn read_new_device_paths(inotify_file: &mut File) -> Vec<String> {
+    let mut buffer = [0u8; 4096];
+    let mut paths = Vec::new();
+
+    loop {
+        let num_bytes = match inotify_file.read(&mut buffer) {
+            Ok(num_bytes) => num_bytes,
+            Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => break,
+            Err(e) => panic!("{}", e),
+        };
+
+        let mut offset = 0;
+        while offset < num_bytes {
+            let event = unsafe {
+                &*(buffer[offset..].as_ptr() as *const libc::inotify_event)
+            };
+            let name_start = offset + mem::size_of::<libc::inotify_event>();
+            let name_end = name_start + event.len as usize;
+            if event.len > 0 {
+                let name = CStr::from_bytes_until_nul(&buffer[name_start..name_end])
+                    .unwrap_or_default()
+                    .to_string_lossy()
+                    .into_owned();
+                paths.push(format!("{}{}", DEV_INPUT_DIR, name));
+            }
+            offset = name_end;
         }
     }
+
+    paths
 }
 
-fn read_input_event(file: &mut impl Read) -> std::io::Result<InputEvent> {
-    let mut buffer = [0u8; 24];
+/// Size of `struct input_event` on this target. `timeval` is two `long`s, so
+/// it's 16 bytes on 64-bit platforms but only 8 bytes on 32-bit ones (e.g.
+/// 32-bit ARM) -- deriving it from `libc::time_t` instead of hardcoding 24
+/// keeps both correct.
+fn input_event_size() -> usize {
+    2 * mem::size_of::<libc::time_t>() + 2 * mem::size_of::<u16>() + mem::size_of::<i32>()
+}
+
+/// Read one raw `struct input_event` frame without decoding it, so callers
+/// in `raw` output mode can re-emit the bytes unmodified.
+fn read_raw_event(file: &mut impl Read) -> std::io::Result<Vec<u8>> {
+    let mut buffer = vec![0u8; input_event_size()];
     file.read_exact(&mut buffer)?;
-    let tv_sec = isize::from_le_bytes(buffer[0..8].try_into().unwrap());
-    let tv_usec = isize::from_le_bytes(buffer[8..16].try_into().unwrap());
-    let type_ = u16::from_le_bytes(buffer[16..18].try_into().unwrap());
-    let code = u16::from_le_bytes(buffer[18..20].try_into().unwrap());
-    let value = i32::from_le_bytes(buffer[20..24].try_into().unwrap());
-    Ok(InputEvent {
+    Ok(buffer)
+}
+
+fn decode_input_event(buffer: &[u8]) -> InputEvent {
+    let time_size = mem::size_of::<libc::time_t>();
+    let (tv_sec, tv_usec) = if time_size == 8 {
+        (
+            i64::from_le_bytes(buffer[0..8].try_into().unwrap()) as isize,
+            i64::from_le_bytes(buffer[8..16].try_into().unwrap()) as isize,
+        )
+    } else {
+        (
+            i32::from_le_bytes(buffer[0..4].try_into().unwrap()) as isize,
+            i32::from_le_bytes(buffer[4..8].try_into().unwrap()) as isize,
+        )
+    };
+
+    let offset = 2 * time_size;
+    let type_ = u16::from_le_bytes(buffer[offset..offset + 2].try_into().unwrap());
+    let code = u16::from_le_bytes(buffer[offset + 2..offset + 4].try_into().unwrap());
+    let value = i32::from_le_bytes(buffer[offset + 4..offset + 8].try_into().unwrap());
+    InputEvent {
         tv_sec,
         tv_usec,
         type_,
         code,
         value,
-    })
+    }
 }
 
 fn main() -> Result<(), Box<dyn Error>> {
@@ -67,42 +265,163 @@ fn main() -> Result<(), Box<dyn Error>> {
         .append(true)
         .open(config.log_file)
         .unwrap_or_else(|e| panic!("{}", e));
-    let mut device_file = File::open(&config.device_file).unwrap_or_else(|e| panic!("{}", e));
 
-    let mut buf: [u8; 24] = [0u8; 24];
+    let epoll_fd = unsafe { libc::epoll_create1(0) };
+    if epoll_fd < 0 {
+        panic!("{}", std::io::Error::last_os_error());
+    }
+
+    // Keyed by raw fd: shift is device-local, so two keyboards pressing
+    // shift independently must not clobber each other's count.
+    let mut devices: HashMap<RawFd, Device> = HashMap::new();
+    for device_file in &config.device_files {
+        let file = File::open(device_file).unwrap_or_else(|e| panic!("{}", e));
+        let fd = file.as_raw_fd();
+        set_nonblocking(fd).unwrap_or_else(|e| panic!("{}", e));
+        epoll_add(epoll_fd, fd).unwrap_or_else(|e| panic!("{}", e));
+        if config.grab {
+            set_grab(fd, 1).unwrap_or_else(|e| panic!("{}", e));
+        }
+        devices.insert(
+            fd,
+            Device {
+                path: device_file.clone(),
+                file,
+                shift_pressed: 0,
+            },
+        );
+    }
+
+    let mut inotify_file = watch_dev_input().unwrap_or_else(|e| panic!("{}", e));
+    let inotify_fd = inotify_file.as_raw_fd();
+    epoll_add(epoll_fd, inotify_fd).unwrap_or_else(|e| panic!("{}", e));
 
-    // We use a u8 here instead of a bool to handle the rare case when both shift keys are pressed
-    // and then one is released
-    let mut shift_pressed = 0;
+    let mut epoll_events = vec![libc::epoll_event { events: 0, u64: 0 }; 16];
     loop {
-        let num_bytes = device_file
-            .read(&mut buf)
-            .unwrap_or_else(|e| panic!("{}", e));
-        if num_bytes != mem::size_of::<InputEvent>() {
-            panic!("Error while reading from device file");
+        if epoll_events.len() < devices.len() + 1 {
+            epoll_events.resize(devices.len() + 1, libc::epoll_event { events: 0, u64: 0 });
         }
-        let event: InputEvent = read_input_event(&mut device_file).unwrap(); //unsafe { mem::transmute(buf) };
-        if is_key_event(event.type_) {
-            if is_key_press(event.value) {
-                if is_shift(event.code) {
-                    shift_pressed += 1;
+
+        let num_ready = unsafe {
+            libc::epoll_wait(
+                epoll_fd,
+                epoll_events.as_mut_ptr(),
+                epoll_events.len() as i32,
+                -1,
+            )
+        };
+        if num_ready < 0 {
+            panic!("{}", std::io::Error::last_os_error());
+        }
+
+        for epoll_event in &epoll_events[..num_ready as usize] {
+            let fd = epoll_event.u64 as RawFd;
+
+            if fd == inotify_fd {
+                for path in read_new_device_paths(&mut inotify_file) {
+                    if !is_known_keyboard_path(&path) {
+                        continue;
+                    }
+                    // Raw frames carry no device tag, so raw mode never logs
+                    // more than one device at a time.
+                    if config.format == Format::Raw && !devices.is_empty() {
+                        debug!("Not attaching {}: raw format logs a single device", path);
+                        continue;
+                    }
+                    let file = match File::open(&path) {
+                        Ok(file) => file,
+                        Err(e) => {
+                            debug!("Failed to open newly-attached device {}: {}", path, e);
+                            continue;
+                        }
+                    };
+                    let new_fd = file.as_raw_fd();
+                    set_nonblocking(new_fd).unwrap_or_else(|e| panic!("{}", e));
+                    epoll_add(epoll_fd, new_fd).unwrap_or_else(|e| panic!("{}", e));
+                    if config.grab {
+                        set_grab(new_fd, 1).unwrap_or_else(|e| panic!("{}", e));
+                    }
+                    debug!("Attached hotplugged device {}", path);
+                    devices.insert(
+                        new_fd,
+                        Device {
+                            path,
+                            file,
+                            shift_pressed: 0,
+                        },
+                    );
+                }
+                continue;
+            }
+
+            let device = match devices.get_mut(&fd) {
+                Some(device) => device,
+                None => continue,
+            };
+
+            let mut unplugged = false;
+            loop {
+                let raw = match read_raw_event(&mut device.file) {
+                    Ok(raw) => raw,
+                    Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => break,
+                    Err(e) => {
+                        debug!("Device {} went away: {}", device.path, e);
+                        unplugged = true;
+                        break;
+                    }
+                };
+
+                if config.format == Format::Raw {
+                    log_file
+                        .write_all(&raw)
+                        .unwrap_or_else(|e| panic!("{}", e));
+                    continue;
                 }
 
-                let text = get_key_text(event.code, shift_pressed).as_bytes();
-                let num_bytes = log_file.write(text).unwrap_or_else(|e| panic!("{}", e));
+                let event = decode_input_event(&raw);
 
-                if num_bytes != text.len() {
-                    panic!("Error while writing to log file");
+                if is_key_event(event.type_) {
+                    if is_key_press(event.value) {
+                        if is_shift(event.code) {
+                            device.shift_pressed += 1;
+                        }
+
+                        let line = match config.format {
+                            Format::Text => {
+                                let key = get_key_text(event.code, device.shift_pressed);
+                                format!("[{}] {}", device.path, key)
+                            }
+                            Format::Jsonl => jsonl_line(device, &event),
+                            Format::Raw => unreachable!(),
+                        };
+                        write_log_line(&mut log_file, &line);
+                    } else if is_key_release(event.value) {
+                        if config.format == Format::Jsonl {
+                            write_log_line(&mut log_file, &jsonl_line(device, &event));
+                        }
+
+                        if is_shift(event.code) {
+                            device.shift_pressed -= 1;
+                        }
+                    }
                 }
-            } else if is_key_release(event.value) {
-                if is_shift(event.code) {
-                    shift_pressed -= 1;
+            }
+
+            if unplugged {
+                if config.grab {
+                    let _ = set_grab(fd, 0);
                 }
+                epoll_remove(epoll_fd, fd);
+                devices.remove(&fd);
             }
         }
     }
 }
 
+fn is_known_keyboard_path(path: &str) -> bool {
+    is_keyboard_device(Path::new(path))
+}
+
 fn parse_args() -> Config {
     fn print_usage(program: &str, opts: Options) {
         let brief = format!("Usage: {} [options]", program);
@@ -114,8 +433,32 @@ fn parse_args() -> Config {
     let mut opts = Options::new();
     opts.optflag("h", "help", "prints this help message");
     opts.optflag("v", "version", "prints the version");
-    opts.optopt("d", "device", "specify the device file", "DEVICE");
+    opts.optmulti(
+        "d",
+        "device",
+        "specify a device file to log; may be given multiple times to log several \
+            keyboards at once",
+        "DEVICE",
+    );
     opts.optopt("f", "file", "specify the file to log to", "FILE");
+    opts.optflag(
+        "g",
+        "grab",
+        "exclusively grab each device so keystrokes aren't delivered to other clients \
+            (without re-injection, the keys won't reach applications)",
+    );
+    opts.optopt(
+        "",
+        "format",
+        "select the log format: text (default), jsonl, or raw",
+        "FORMAT",
+    );
+    opts.optflag(
+        "",
+        "all",
+        "when no `-d` is given and multiple keyboards are detected, log all of them \
+            instead of requiring an explicit `-d`",
+    );
 
     let matches = opts.parse(&args[1..]).unwrap_or_else(|e| panic!("{}", e));
     if matches.opt_present("h") {
@@ -128,49 +471,140 @@ fn parse_args() -> Config {
         exit(0);
     }
 
-    let device_file = matches.opt_str("d").unwrap_or_else(|| get_default_device());
+    let device_files = matches.opt_strs("d");
+    let device_files = if device_files.is_empty() {
+        get_default_devices(matches.opt_present("all"))
+    } else {
+        device_files
+    };
     let log_file = matches.opt_str("f").unwrap_or("keys.log".to_owned());
+    let grab = matches.opt_present("g");
+    let format = matches
+        .opt_str("format")
+        .map(|s| s.parse().unwrap_or_else(|e| panic!("{}", e)))
+        .unwrap_or(Format::Text);
 
-    Config::new(device_file, log_file)
+    if format == Format::Raw && device_files.len() > 1 {
+        panic!(
+            "--format raw doesn't tag frames with a device, so it can't be used with \
+                more than one device at once; pass a single `-d` or use `text`/`jsonl` instead"
+        );
+    }
+
+    Config::new(device_files, log_file, grab, format)
 }
 
-fn get_default_device() -> String {
-    let mut filenames = get_keyboard_device_filenames();
+fn get_default_devices(all: bool) -> Vec<String> {
+    let filenames = get_keyboard_device_filenames();
     debug!("Detected devices: {:?}", filenames);
 
-    if filenames.len() == 1 {
-        filenames.swap_remove(0)
-    } else {
+    if filenames.is_empty() {
+        panic!("No keyboard devices were detected. Please specify one using the `-d` flag");
+    }
+
+    if filenames.len() > 1 && !all {
         panic!(
-            "The following keyboard devices were detected: {:?}. Please select one using \
-                the `-d` flag",
+            "The following keyboard devices were detected: {:?}. Please select one (or \
+                several) using the `-d` flag, or pass `--all` to log every detected device",
             filenames
         );
     }
+
+    filenames
 }
 
-// Detects and returns the name of the keyboard device file. This function uses
-// the fact that all device information is shown in /proc/bus/input/devices and
-// the keyboard device file should always have an EV of 120013
-fn get_keyboard_device_filenames() -> Vec<String> {
-    let mut command_str = "grep -E 'Handlers|EV' /proc/bus/input/devices".to_string();
-    command_str.push_str("| grep -B1 120013");
-    command_str.push_str("| grep -Eo event[0-9]+");
-
-    let res = Command::new("sh")
-        .arg("-c")
-        .arg(command_str)
-        .output()
-        .unwrap_or_else(|e| {
-            panic!("{}", e);
-        });
-    let res_str = std::str::from_utf8(&res.stdout).unwrap();
+// Highest `KEY_*` code defined by linux/input-event-codes.h. Used to size the
+// EVIOCGBIT capability bitmask.
+const KEY_MAX: usize = 0x2ff;
+const KEY_A: usize = 30;
+
+// The ioctls below aren't exposed by the `libc` crate, so their `_IOC`
+// encoding (from linux/ioctl.h) is reproduced here.
+const IOC_NRSHIFT: u32 = 0;
+const IOC_TYPESHIFT: u32 = 8;
+const IOC_SIZESHIFT: u32 = 16;
+const IOC_DIRSHIFT: u32 = 30;
+const IOC_WRITE: libc::c_ulong = 1;
+const IOC_READ: libc::c_ulong = 2;
+
+fn ioc(dir: libc::c_ulong, ty: u8, nr: u32, size: usize) -> libc::c_ulong {
+    (dir << IOC_DIRSHIFT)
+        | ((ty as libc::c_ulong) << IOC_TYPESHIFT)
+        | ((nr as libc::c_ulong) << IOC_NRSHIFT)
+        | ((size as libc::c_ulong) << IOC_SIZESHIFT)
+}
+
+fn eviocgbit(ev: u32, len: usize) -> libc::c_ulong {
+    ioc(IOC_READ, b'E', 0x20 + ev, len)
+}
+
+fn eviocgrab() -> libc::c_ulong {
+    ioc(IOC_WRITE, b'E', 0x90, mem::size_of::<libc::c_int>())
+}
+
+/// Query `path`'s supported-key bitmask via `EVIOCGBIT` and treat it as a
+/// keyboard if the bit for `KEY_A` is set. This is the kernel's own way of
+/// advertising capabilities and works without `/proc` or any locale-specific
+/// text parsing.
+fn is_keyboard_device(path: &Path) -> bool {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(_) => return false,
+    };
+
+    let mut bitmask = vec![0u8; KEY_MAX / 8 + 1];
+    let res = unsafe {
+        libc::ioctl(
+            file.as_raw_fd(),
+            eviocgbit(input::EV_KEY as u32, bitmask.len()),
+            bitmask.as_mut_ptr(),
+        )
+    };
+    if res < 0 {
+        return false;
+    }
+
+    (bitmask[KEY_A / 8] >> (KEY_A % 8)) & 1 == 1
+}
 
+/// Take (`grab == 1`) or release (`grab == 0`) an exclusive `EVIOCGRAB` on
+/// `fd`, so no other client on the system receives these keystrokes. The
+/// kernel also releases the grab automatically when `fd` is closed, which
+/// covers ordinary process shutdown.
+fn set_grab(fd: RawFd, grab: libc::c_int) -> std::io::Result<()> {
+    let res = unsafe { libc::ioctl(fd, eviocgrab(), grab) };
+    if res < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Enumerate `/dev/input/event*` and return the ones that look like
+/// keyboards according to `is_keyboard_device()`.
+fn get_keyboard_device_filenames() -> Vec<String> {
     let mut filenames = Vec::new();
-    for file in res_str.trim().split('\n') {
-        let mut filename = "/dev/input/".to_string();
-        filename.push_str(file);
-        filenames.push(filename);
+
+    let entries = match std::fs::read_dir(DEV_INPUT_DIR) {
+        Ok(entries) => entries,
+        Err(e) => panic!("{}", e),
+    };
+
+    for entry in entries {
+        let entry = entry.unwrap_or_else(|e| panic!("{}", e));
+        let path = entry.path();
+        let is_event_node = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .map(|name| name.starts_with("event"))
+            .unwrap_or(false);
+        if !is_event_node {
+            continue;
+        }
+
+        if is_keyboard_device(&path) {
+            filenames.push(path.to_string_lossy().into_owned());
+        }
     }
+
     filenames
 }